@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+// The sampling task is the only consumer, and it already owns the sensors
+// and filters, so commands are parsed as soon as they arrive off the wire
+// and handed over as plain data rather than acted on from the MQTT thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tank {
+    ClearTank,
+    Bioreactor,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    SetSamplingPeriod(Duration),
+    SetFilterWindow(usize),
+    ReadNow(Tank),
+    GetState,
+    // Firmware image bytes and the commit signature arrive as raw MQTT
+    // payloads rather than through the text grammar below, so the MQTT
+    // thread builds these directly instead of going through `Command::parse`.
+    OtaChunk(Vec<u8>),
+    OtaCommit([u8; 64]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    Malformed,
+}
+
+impl core::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "malformed command")
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl Command {
+    // A deliberately tiny grammar: one command name and at most one
+    // argument, space separated, ASCII. `greywater/cmd` payloads look like
+    // `set-period 30`, `set-window 3`, `read clear-tank`, `get-state`.
+    pub fn parse(payload: &[u8]) -> Result<Command, CommandError> {
+        let text = core::str::from_utf8(payload).map_err(|_| CommandError::Malformed)?;
+        let mut parts = text.trim().split_whitespace();
+
+        match parts.next() {
+            Some("set-period") => {
+                let secs = parts.next().ok_or(CommandError::Malformed)?
+                    .parse().map_err(|_| CommandError::Malformed)?;
+                Ok(Command::SetSamplingPeriod(Duration::from_secs(secs)))
+            }
+            Some("set-window") => {
+                let window = parts.next().ok_or(CommandError::Malformed)?
+                    .parse().map_err(|_| CommandError::Malformed)?;
+                Ok(Command::SetFilterWindow(window))
+            }
+            Some("read") => match parts.next() {
+                Some("clear-tank") => Ok(Command::ReadNow(Tank::ClearTank)),
+                Some("bioreactor") => Ok(Command::ReadNow(Tank::Bioreactor)),
+                _ => Err(CommandError::Malformed),
+            },
+            Some("get-state") => Ok(Command::GetState),
+            _ => Err(CommandError::Malformed),
+        }
+    }
+}
+
+// Published to `greywater/state` in response to `get-state`. Acks for the
+// commands that change it carry a short text message, not this struct.
+#[derive(Debug, Serialize)]
+pub struct SamplingState {
+    pub sampling_period_secs: u64,
+    pub filter_window: usize,
+}
@@ -0,0 +1,268 @@
+use esp_idf_svc::wifi::EspWifi;
+use esp_idf_svc::netif::EspNetifStack;
+use esp_idf_svc::sysloop::EspSysLoopStack;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use embedded_svc::wifi::{
+    ClientConfiguration, ClientConnectionStatus, ClientIpStatus, ClientStatus, Configuration, Status, Wifi,
+};
+
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+use smoltcp::iface::{EthernetInterfaceBuilder, Interface, NeighborCache};
+use smoltcp::phy::{Device, DeviceCapabilities, RxToken, TxToken};
+use smoltcp::socket::SocketSet;
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpCidr, Ipv4Cidr};
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::*;
+
+// ENC424J600's single-packet RX/TX buffers are smaller than a jumbo frame,
+// but comfortably fit a standard Ethernet MTU.
+const MAX_FRAME_LEN: usize = 1518;
+
+// A tank sitting next to a pump and a few hundred pounds of greywater is a
+// rough neighborhood for 2.4GHz Wi-Fi. `Transport` lets the firmware bring
+// up whichever link the install actually has -- Wi-Fi by default, wired
+// Ethernet where the metal and motors make that unreliable -- chosen at
+// build time, with `SensorDataPublisher` none the wiser about which one.
+pub trait Transport {
+    // Bring the physical link up. Once this returns, the link has an IP
+    // address and traffic can flow.
+    fn bring_up(&mut self) -> Result<()>;
+}
+
+// Transports that register with esp-idf's netif/lwIP stack, so
+// `EspMqttClient` can dial out over them without further plumbing.
+// `EthernetTransport` brings up a link of its own via smoltcp rather than
+// lwIP, so `EspMqttClient` can't speak over it as-is -- it deliberately
+// isn't one of these yet, and `SensorDataPublisher::connect` is typed to
+// only accept transports that are.
+pub trait NetifTransport: Transport {}
+
+pub struct WifiTransport {
+    wifi: EspWifi,
+    ssid: String,
+}
+
+impl WifiTransport {
+    pub fn new(ssid: &str, pass: &str) -> Result<WifiTransport> {
+        let netif_stack = Arc::new(EspNetifStack::new()?);
+        let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
+        let default_nvs = Arc::new(EspDefaultNvs::new()?);
+
+        let mut wifi = EspWifi::new(netif_stack, sys_loop_stack, default_nvs)?;
+
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+            ssid: ssid.into(),
+            password: pass.into(),
+            ..Default::default()
+        }))?;
+
+        Ok(WifiTransport { wifi, ssid: ssid.into() })
+    }
+}
+
+impl Transport for WifiTransport {
+    fn bring_up(&mut self) -> Result<()> {
+        info!("Connecting to wifi: {}", self.ssid);
+
+        // EspWifi starts associating as part of configuration, and registers
+        // with esp-idf's netif/lwIP stack, so EspMqttClient can dial out
+        // over it without any further plumbing here. What's left is to wait
+        // for the association and the IP lease to settle.
+        self.wifi
+            .wait_status_with_timeout(std::time::Duration::from_secs(20), |status| !status.is_transitional())
+            .map_err(|e| anyhow::anyhow!("Unexpected Wifi status: {:?}", e))?;
+
+        match self.wifi.get_status() {
+            Status(ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(_))), _) => Ok(()),
+            status => Err(anyhow::anyhow!("Wifi did not get an IP address: {:?}", status)),
+        }
+    }
+}
+
+impl NetifTransport for WifiTransport {}
+
+// Drives an ENC424J600-class SPI Ethernet controller directly with
+// smoltcp rather than through esp-idf's Ethernet driver, so it works on
+// parts esp-idf has no driver for.
+pub struct EthernetTransport<'a, SPI, CS> {
+    iface: Interface<'a, EthDevice<SPI, CS>>,
+    sockets: SocketSet<'a>,
+}
+
+// NOTE: smoltcp owns its own TCP/IP stack independent of esp-idf's lwIP,
+// so `EspMqttClient` can't speak over it as-is; wiring an MQTT client onto
+// `sockets` here is the next step, not yet done. Until then this only
+// implements `Transport`, not `NetifTransport`, so it can't be handed to
+// `SensorDataPublisher::connect` -- a build that wants wired Ethernet isn't
+// wired up end to end yet.
+impl<'a, SPI, CS> EthernetTransport<'a, SPI, CS>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+{
+    pub fn new(spi: SPI, cs: CS, mac: EthernetAddress, ip: Ipv4Cidr) -> EthernetTransport<'a, SPI, CS> {
+        let device = EthDevice { spi, cs };
+        let neighbor_cache = NeighborCache::new(std::collections::BTreeMap::new());
+
+        let iface = EthernetInterfaceBuilder::new(device)
+            .ethernet_addr(mac)
+            .neighbor_cache(neighbor_cache)
+            .ip_addrs([IpCidr::Ipv4(ip)])
+            .finalize();
+
+        EthernetTransport { iface, sockets: SocketSet::new(Vec::new()) }
+    }
+}
+
+impl<'a, SPI, CS> Transport for EthernetTransport<'a, SPI, CS>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+{
+    fn bring_up(&mut self) -> Result<()> {
+        info!("Bringing up wired ethernet link");
+
+        // SysTick drives smoltcp's clock; this just confirms one poll
+        // succeeds so a disconnected/unresponsive controller fails fast
+        // at startup instead of silently never publishing.
+        self.iface.poll(&mut self.sockets, Instant::from_millis(systick_millis()))
+            .map_err(|e| anyhow::anyhow!("Ethernet link poll failed: {}", e))?;
+
+        Ok(())
+    }
+}
+
+// Bridges an ENC424J600 over SPI to smoltcp's `Device` trait. Frame I/O
+// goes through the controller's packet buffer opcodes (`RBM`/`WBM`) rather
+// than addressing its banked registers directly, which is enough for
+// smoltcp to hand us whole frames to parse and build.
+struct EthDevice<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI, CS> EthDevice<SPI, CS>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+{
+    // Reads ESTAT and checks PKTIF, which the controller sets whenever the
+    // receive buffer holds at least one complete packet (and clears once
+    // the host has read the last one out). `receive()` has to check this
+    // before clocking out a frame -- RBM has no concept of "nothing to
+    // read" and will happily shift back whatever garbage currently sits
+    // at the buffer read pointer.
+    fn rx_packet_pending(&mut self) -> Result<bool, ()> {
+        self.cs.set_low().map_err(|_| ())?;
+        let mut frame = [0x30u8, 0x1a, 0x00]; // RCRU opcode, ESTAT register, dummy byte to clock the value back in
+        self.spi.transfer(&mut frame).map_err(|_| ())?;
+        self.cs.set_high().map_err(|_| ())?;
+        Ok(frame[2] & 0x40 != 0) // PKTIF
+    }
+
+    // Read Buffer Memory: clock out the opcode, then `len` don't-care
+    // bytes to shift the received frame back in.
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+        self.cs.set_low().map_err(|_| ())?;
+        let mut opcode = [0x20u8]; // RBM
+        self.spi.transfer(&mut opcode).map_err(|_| ())?;
+        self.spi.transfer(buf).map_err(|_| ())?;
+        self.cs.set_high().map_err(|_| ())?;
+        Ok(buf.len())
+    }
+
+    // Write Buffer Memory: clock out the opcode, then the frame bytes.
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), ()> {
+        self.cs.set_low().map_err(|_| ())?;
+        let mut opcode = [0x22u8]; // WBM
+        self.spi.transfer(&mut opcode).map_err(|_| ())?;
+        let mut owned = frame.to_vec();
+        self.spi.transfer(&mut owned).map_err(|_| ())?;
+        self.cs.set_high().map_err(|_| ())?;
+        Ok(())
+    }
+}
+
+impl<'d, SPI, CS> Device<'d> for EthDevice<SPI, CS>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+{
+    type RxToken = EthRxToken;
+    type TxToken = EthTxToken<'d, SPI, CS>;
+
+    fn receive(&'d mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        if !self.rx_packet_pending().ok()? {
+            return None;
+        }
+
+        let mut buffer = vec![0u8; MAX_FRAME_LEN];
+        let len = self.read_frame(&mut buffer).ok()?;
+        buffer.truncate(len);
+
+        Some((EthRxToken { buffer }, EthTxToken { device: self }))
+    }
+
+    fn transmit(&'d mut self) -> Option<Self::TxToken> {
+        Some(EthTxToken { device: self })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = MAX_FRAME_LEN;
+        caps
+    }
+}
+
+struct EthRxToken {
+    buffer: Vec<u8>,
+}
+
+impl RxToken for EthRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(&mut self.buffer)
+    }
+}
+
+struct EthTxToken<'d, SPI, CS> {
+    device: &'d mut EthDevice<SPI, CS>,
+}
+
+impl<'d, SPI, CS> TxToken for EthTxToken<'d, SPI, CS>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+{
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut buffer = vec![0u8; len];
+        let result = f(&mut buffer)?;
+        self.device.write_frame(&buffer).map_err(|_| smoltcp::Error::Illegal)?;
+        Ok(result)
+    }
+}
+
+fn systick_millis() -> i64 {
+    // esp-idf-hal's SysTick-backed delay doesn't expose a free-running
+    // counter directly; `EspSystemTime` is close enough for the purposes
+    // of smoltcp's `Instant` clock.
+    use embedded_svc::sys_time::SystemTime;
+    esp_idf_svc::systime::EspSystemTime {}.now().as_millis() as i64
+}
+
+// `SensorDataPublisher::connect` brings the transport up itself, so this
+// just gets a `WifiTransport` configured and ready to hand over.
+pub fn connect_to_wifi(ssid: &str, pass: &str) -> Result<WifiTransport> {
+    WifiTransport::new(ssid, pass)
+}
@@ -0,0 +1,5 @@
+pub mod comms;
+pub mod command;
+pub mod ota;
+pub mod sensors;
+pub mod telemetry;
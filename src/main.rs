@@ -11,7 +11,7 @@ use esp_idf_hal::{
 };
 
 use embedded_svc::{
-    mqtt::client::{Connection, MessageId, MessageImpl, utils::ConnState, Publish, QoS},
+    mqtt::client::{Client, Connection, Event, MessageId, MessageImpl, utils::ConnState, Publish, QoS},
     sys_time::SystemTime,
     timer::*,
 };
@@ -25,22 +25,43 @@ use esp_idf_svc::{
 use esp_idf_sys::EspError;
 
 use anyhow::Result;
-use generic_array::typenum::U5;
-use heapless::spsc::Queue;
+use embassy_executor::Executor;
+use embassy_futures::join::join;
+use embassy_time::{Duration as EmbassyDuration, Timer};
+use heapless::spsc::{Consumer, Producer, Queue};
+use static_cell::StaticCell;
 
 use log::*;
-use median::stack::Filter;
+use median::heap::Filter;
 use ssd1306::mode::DisplayConfig;
 
-use greywater::{comms, hc_sr04, sensors::UltrasonicSensor};
+use greywater::{
+    command::{Command, SamplingState, Tank},
+    comms::{self, NetifTransport},
+    hc_sr04,
+    ota::{OtaStatus, OtaUpdate},
+    sensors::{Echo, HcSr04, SensorError, UltrasonicSensor},
+    telemetry::{SensorId, Telemetry},
+};
 
 
 const SSID: &str = env!("GREYWATER_WIFI_SSID");
 const PASS: &str = env!("GREYWATER_WIFI_PASS");
 const MQTT: &str = env!("GREYWATER_MQTT");
 
-static mut CLEAN_TANK_QUEUE: Queue<Duration, 2> = Queue::new();
-static mut BIOREACTOR_TANK_QUEUE: Queue<Duration, 2> = Queue::new();
+const COMMAND_TOPIC: &str = "greywater/cmd";
+const ACK_TOPIC: &str = "greywater/cmd/ack";
+const STATE_TOPIC: &str = "greywater/state";
+const OTA_IMAGE_TOPIC: &str = "greywater/ota/image";
+const OTA_COMMIT_TOPIC: &str = "greywater/ota/commit";
+const OTA_STATUS_TOPIC: &str = "greywater/ota/status";
+
+static CLEAN_TANK_ECHO: Echo = Echo::new();
+static BIOREACTOR_ECHO: Echo = Echo::new();
+
+static mut COMMAND_QUEUE: Queue<Command, 8> = Queue::new();
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
 
 fn main() -> Result<()> {
 
@@ -51,9 +72,9 @@ fn main() -> Result<()> {
     let pins = peripherals.pins;
 
     // Clearwater: GPIO 0 and 1
-    let mut clearwater_sensor = hc_sr04!(pins.gpio0, pins.gpio1, CLEAN_TANK_QUEUE);
+    let clearwater_sensor = hc_sr04!(pins.gpio0, pins.gpio1, CLEAN_TANK_ECHO);
     // Clearwater: GPIO 2 and 3
-    let mut bioreactor_sensor = hc_sr04!(pins.gpio2, pins.gpio3, BIOREACTOR_TANK_QUEUE);
+    let bioreactor_sensor = hc_sr04!(pins.gpio2, pins.gpio3, BIOREACTOR_ECHO);
 
     // Display: GPIO 6 and 7
     let mut display = {
@@ -74,35 +95,219 @@ fn main() -> Result<()> {
         display
     };
 
+    // `embassy_executor::task` needs a concrete (non-generic) signature to
+    // size the task's static allocation, so the sampling task takes a
+    // trait object rather than `impl core::fmt::Write`.
+    let display: &'static mut dyn core::fmt::Write = Box::leak(Box::new(display));
+
     let mut delay = delay::Ets;
 
     write!(display, "Starting wifi...")?;
-    let _wifi = comms::connect_to_wifi(SSID, PASS);
+    let mut transport = comms::connect_to_wifi(SSID, PASS)?;
+
+    let (command_tx, command_rx) = unsafe { COMMAND_QUEUE.split() };
 
-    let mut publisher = SensorDataPublisher::connect(MQTT, &MqttClientConfiguration {
+    let publisher = SensorDataPublisher::connect(&mut transport, MQTT, &MqttClientConfiguration {
         client_id: Some("greywater"),
 
         ..Default::default()
-    })?;
-
+    }, command_tx)?;
 
     // Just let things settle
     delay.delay_ms(10u8);
     info!("Starting distance");
 
-    let mut clear_filter: Filter<f32, U5> = Filter::new();
-    let mut bioreactor_filter: Filter<f32, U5> = Filter::new();
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(sample_tanks(clearwater_sensor, bioreactor_sensor, display, publisher, command_rx))
+            .expect("Spawning sampling task");
+    })
+}
+
+// A timed-out or errored reading isn't a sample, so it's dropped instead
+// of being fed into the median filter. Returns the raw reading so callers
+// can track it and how many samples actually landed this round.
+async fn sample(
+    sensor: &mut impl UltrasonicSensor,
+    filter: &mut Filter<f32>,
+    name: &str,
+) -> Option<f32> {
+    match sensor.distance_in_cms().await {
+        Ok(distance) => {
+            filter.consume(distance);
+            Some(distance)
+        }
+        Err(err @ SensorError::Timeout) => {
+            warn!("{} read timed out: {}", name, err);
+            None
+        }
+        Err(err) => {
+            error!("{} read failed: {}", name, err);
+            None
+        }
+    }
+}
+
+const DEFAULT_FILTER_WINDOW: usize = 5;
+// Bounds how much history `SetFilterWindow` can ask the median filter to
+// hold onto; high enough to smooth out a jittery sensor, low enough that a
+// retune doesn't take ages to refill before it starts reporting again.
+const MAX_FILTER_WINDOW: usize = 32;
+
+// `heapless::spsc::Consumer` has no waker support, so there's no `select`
+// to race it against a timer directly. Polling in short slices gets the
+// same effect: a command that arrives mid-sleep is noticed within one
+// `COMMAND_POLL_INTERVAL`, instead of sitting queued until `duration` runs out.
+const COMMAND_POLL_INTERVAL: EmbassyDuration = EmbassyDuration::from_millis(50);
+
+async fn sleep_or_command_ready(commands: &Consumer<'static, Command, 8>, duration: EmbassyDuration) {
+    let mut remaining = duration;
+    while remaining > EmbassyDuration::from_ticks(0) {
+        if commands.ready() {
+            return;
+        }
+        let tick = core::cmp::min(remaining, COMMAND_POLL_INTERVAL);
+        Timer::after(tick).await;
+        remaining -= tick;
+    }
+}
+
+#[embassy_executor::task]
+async fn sample_tanks(
+    mut clearwater_sensor: HcSr04,
+    mut bioreactor_sensor: HcSr04,
+    mut display: &'static mut dyn core::fmt::Write,
+    mut publisher: SensorDataPublisher,
+    mut commands: Consumer<'static, Command, 8>,
+) {
+    let mut filter_window = DEFAULT_FILTER_WINDOW;
+    let mut clear_filter: Filter<f32> = Filter::new(filter_window);
+    let mut bioreactor_filter: Filter<f32> = Filter::new(filter_window);
+
+    let mut sampling_period = EmbassyDuration::from_secs(10);
+    let mut ota_update: Option<OtaUpdate> = None;
+
+    loop {
+        while let Some(command) = commands.dequeue() {
+            match command {
+                Command::SetSamplingPeriod(period) => {
+                    sampling_period = EmbassyDuration::from_secs(period.as_secs());
+                    info!("Sampling period set to {:?}", period);
+                    ack(&mut publisher, "sampling period updated");
+                }
+                Command::SetFilterWindow(requested) => {
+                    filter_window = requested.clamp(1, MAX_FILTER_WINDOW);
+                    clear_filter = Filter::new(filter_window);
+                    bioreactor_filter = Filter::new(filter_window);
+                    info!("Filter window set to {}", filter_window);
+                    ack(&mut publisher, "filter window updated");
+                }
+                Command::ReadNow(Tank::ClearTank) => {
+                    if let Some(raw) = sample(&mut clearwater_sensor, &mut clear_filter, "clear tank").await {
+                        let telemetry = Telemetry::new(SensorId::ClearTank, raw, raw, 1);
+                        if let Err(err) = publisher.publish_clear_tank(&telemetry) {
+                            error!("Unable to publish on-demand clear tank reading: {}", err);
+                        }
+                    }
+                    ack(&mut publisher, "clear tank read");
+                }
+                Command::ReadNow(Tank::Bioreactor) => {
+                    if let Some(raw) = sample(&mut bioreactor_sensor, &mut bioreactor_filter, "bioreactor").await {
+                        let telemetry = Telemetry::new(SensorId::Bioreactor, raw, raw, 1);
+                        if let Err(err) = publisher.publish_bioreactor(&telemetry) {
+                            error!("Unable to publish on-demand bioreactor reading: {}", err);
+                        }
+                    }
+                    ack(&mut publisher, "bioreactor read");
+                }
+                Command::GetState => {
+                    let state = SamplingState {
+                        sampling_period_secs: sampling_period.as_secs(),
+                        filter_window,
+                    };
+                    if let Err(err) = publisher.publish_state(&state) {
+                        error!("Unable to publish state: {}", err);
+                    }
+                    ack(&mut publisher, "state published");
+                }
+                Command::OtaChunk(chunk) => {
+                    let update = match ota_update.as_mut() {
+                        Some(update) => update,
+                        None => {
+                            match OtaUpdate::begin() {
+                                Ok(update) => {
+                                    report_ota(&mut publisher, OtaStatus::Started);
+                                    ota_update.insert(update)
+                                }
+                                Err(err) => {
+                                    error!("Unable to start OTA update: {}", err);
+                                    report_ota(&mut publisher, OtaStatus::Failed { reason: err.to_string() });
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+
+                    match update.write_chunk(&chunk) {
+                        Ok(bytes_written) => report_ota(&mut publisher, OtaStatus::Progress { bytes_written }),
+                        Err(err) => {
+                            error!("OTA write failed: {}", err);
+                            report_ota(&mut publisher, OtaStatus::Failed { reason: err.to_string() });
+                            if let Some(update) = ota_update.take() {
+                                update.abort();
+                            }
+                        }
+                    }
+                }
+                Command::OtaCommit(signature) => match ota_update.take() {
+                    Some(update) => match update.finish(&signature) {
+                        Ok(()) => {
+                            report_ota(&mut publisher, OtaStatus::Success);
+                            greywater::ota::reboot_into_new_image();
+                        }
+                        Err(err) => {
+                            error!("OTA verification failed: {}", err);
+                            report_ota(&mut publisher, OtaStatus::VerifyFailed);
+                        }
+                    },
+                    None => warn!("Ignoring OTA commit with no image in progress"),
+                },
+            }
+        }
 
-    let mut periodic = EspTimerService::new().expect("Setting timer service").timer(move || {
         debug!("Sampling");
 
-        for _ in 0..5 {
+        let mut clear_raw = 0.0;
+        let mut clear_samples = 0usize;
+        let mut bioreactor_raw = 0.0;
+        let mut bioreactor_samples = 0usize;
+        let mut interrupted = false;
+
+        for _ in 0..filter_window {
             debug!("Consuming from clear");
-            clear_filter.consume(clearwater_sensor.distance_in_cms());
+            if let Some(raw) = sample(&mut clearwater_sensor, &mut clear_filter, "clear tank").await {
+                clear_raw = raw;
+                clear_samples += 1;
+            }
             debug!("Consuming from bioreactor");
-            bioreactor_filter.consume(bioreactor_sensor.distance_in_cms());
+            if let Some(raw) = sample(&mut bioreactor_sensor, &mut bioreactor_filter, "bioreactor").await {
+                bioreactor_raw = raw;
+                bioreactor_samples += 1;
+            }
             debug!("Settling.");
-            delay.delay_ms(100u8);
+            sleep_or_command_ready(&commands, EmbassyDuration::from_millis(100)).await;
+            if commands.ready() {
+                // A command arrived mid-round; go handle it immediately
+                // rather than finishing this round first. Nothing is
+                // published for this round; the samples already fed into
+                // the filters stay there for the next round's median.
+                interrupted = true;
+                break;
+            }
+        }
+
+        if interrupted {
+            continue;
         }
 
         debug!("Checking median");
@@ -111,31 +316,43 @@ fn main() -> Result<()> {
 
         info!("Clear Tank: {}", clear_distance);
         info!("Bioreactor Tank: {}", bioreactor_distance);
-        display.clear().unwrap();
-        write!(display, "Clear: {:.0}cm\n\n", clear_distance).unwrap();
-        write!(display, "Reactor: {:.0}cm\n", bioreactor_distance).unwrap();
 
-        if let Err(err) = publisher.publish_clear_tank(clear_distance) {
-            error!("Unable to publish clear tank distance: {}", err);
-        }
-
-        if let Err(err) = publisher.publish_bioreactor(bioreactor_distance) {
-            error!("Unable to publish bioreactor distance: {}", err);
-        }
-    }).expect("Periodic timer setup");
-
-    periodic.every(Duration::from_secs(10)).expect("Schedule sampling");
-
-    debug!("Timer scheduled");
+        let clear_telemetry = Telemetry::new(SensorId::ClearTank, clear_distance, clear_raw, clear_samples);
+        let bioreactor_telemetry = Telemetry::new(SensorId::Bioreactor, bioreactor_distance, bioreactor_raw, bioreactor_samples);
+
+        // The display write doesn't depend on the publishes, so the two are
+        // joined rather than chained. Both publishes need `publisher`, so
+        // they share one future and run one after the other. None of these
+        // calls awaits, so each future completes the first time it's polled.
+        join(
+            async {
+                write!(display, "Clear: {:.0}cm\n\n", clear_distance).unwrap();
+                write!(display, "Reactor: {:.0}cm\n", bioreactor_distance).unwrap();
+            },
+            async {
+                if let Err(err) = publisher.publish_clear_tank(&clear_telemetry) {
+                    error!("Unable to publish clear tank telemetry: {}", err);
+                }
+                if let Err(err) = publisher.publish_bioreactor(&bioreactor_telemetry) {
+                    error!("Unable to publish bioreactor telemetry: {}", err);
+                }
+            },
+        ).await;
 
-    loop_forever()
+        sleep_or_command_ready(&commands, sampling_period).await;
+    }
 }
 
-#[allow(dead_code, unreachable_code)]
-fn loop_forever() -> Result<()> {
-    loop { }
+fn ack(publisher: &mut SensorDataPublisher, message: &str) {
+    if let Err(err) = publisher.publish_ack(message) {
+        error!("Unable to publish command ack: {}", err);
+    }
+}
 
-    Ok(())
+fn report_ota(publisher: &mut SensorDataPublisher, status: OtaStatus) {
+    if let Err(err) = publisher.publish_ota_status(&status) {
+        error!("Unable to publish OTA status: {}", err);
+    }
 }
 
 #[allow(dead_code)] // The listener_handle just needs to hold the thread reference
@@ -145,17 +362,59 @@ struct SensorDataPublisher {
 }
 
 impl SensorDataPublisher {
-    fn connect(address: &str, config: &MqttClientConfiguration) -> Result<Self> {
-        let (mqtt_client, mut mqtt_conn) =
+    fn connect(
+        transport: &mut impl NetifTransport,
+        address: &str,
+        config: &MqttClientConfiguration,
+        mut commands: Producer<'static, Command, 8>,
+    ) -> Result<Self> {
+        transport.bring_up()?;
+
+        let (mut mqtt_client, mut mqtt_conn) =
             EspMqttClient::new_with_conn(address, config)?;
 
+        mqtt_client.subscribe(COMMAND_TOPIC, QoS::AtMostOnce)?;
+        mqtt_client.subscribe(OTA_IMAGE_TOPIC, QoS::AtMostOnce)?;
+        mqtt_client.subscribe(OTA_COMMIT_TOPIC, QoS::AtMostOnce)?;
+
         let listener_handle = std::thread::spawn(move || {
             debug!("MQTT Listening for messages");
 
             while let Some(msg) = mqtt_conn.next() {
-                match msg {
-                    Err(e) => debug!("MQTT Message ERROR: {}", e),
-                    Ok(msg) => debug!("MQTT Message: {:?}", msg),
+                let command = match msg {
+                    Err(e) => {
+                        debug!("MQTT Message ERROR: {}", e);
+                        continue;
+                    }
+                    Ok(Event::Received(msg)) if msg.topic().as_deref() == Some(COMMAND_TOPIC) => {
+                        match Command::parse(msg.data()) {
+                            Ok(command) => command,
+                            Err(err) => {
+                                warn!("Ignoring malformed command: {}", err);
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(Event::Received(msg)) if msg.topic().as_deref() == Some(OTA_IMAGE_TOPIC) => {
+                        Command::OtaChunk(msg.data().to_vec())
+                    }
+                    Ok(Event::Received(msg)) if msg.topic().as_deref() == Some(OTA_COMMIT_TOPIC) => {
+                        match <[u8; 64]>::try_from(msg.data()) {
+                            Ok(signature) => Command::OtaCommit(signature),
+                            Err(_) => {
+                                warn!("Ignoring OTA commit with a malformed signature");
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(msg) => {
+                        debug!("MQTT Message: {:?}", msg);
+                        continue;
+                    }
+                };
+
+                if commands.enqueue(command).is_err() {
+                    warn!("Command queue full, dropping command");
                 }
             }
 
@@ -165,27 +424,42 @@ impl SensorDataPublisher {
         Ok(SensorDataPublisher { mqtt_client, listener_handle })
     }
 
-    fn publish_clear_tank(&mut self, distance: f32) -> Result<MessageId> {
-        self.publish("greywater/clean-tank", distance)
+    fn publish_clear_tank(&mut self, telemetry: &Telemetry) -> Result<MessageId> {
+        self.publish("greywater/clean-tank", telemetry)
     }
 
-    fn publish_bioreactor(&mut self, distance: f32) -> Result<MessageId> {
-        self.publish("greywater/bioreactor", distance)
+    fn publish_bioreactor(&mut self, telemetry: &Telemetry) -> Result<MessageId> {
+        self.publish("greywater/bioreactor", telemetry)
     }
 
-    fn publish(&mut self, topic: &str, distance: f32) -> Result<MessageId> {
+    fn publish(&mut self, topic: &str, telemetry: &Telemetry) -> Result<MessageId> {
         debug!("Publishing to mqtt topic: {}", topic);
 
         let result = self.mqtt_client.publish(
             topic,
             QoS::AtMostOnce,
             false,
-            format!("{{ \"raw_distance\": {} }}", distance).as_bytes(),
+            &telemetry.encode()?,
         )?;
 
         debug!("done publishing");
         Ok(result)
     }
-}
 
+    fn publish_ack(&mut self, message: &str) -> Result<MessageId> {
+        debug!("Publishing ack: {}", message);
+        Ok(self.mqtt_client.publish(ACK_TOPIC, QoS::AtMostOnce, false, message.as_bytes())?)
+    }
+
+    fn publish_state(&mut self, state: &SamplingState) -> Result<MessageId> {
+        debug!("Publishing state");
+        let payload = serde_json::to_vec(state)?;
+        Ok(self.mqtt_client.publish(STATE_TOPIC, QoS::AtMostOnce, false, &payload)?)
+    }
 
+    fn publish_ota_status(&mut self, status: &OtaStatus) -> Result<MessageId> {
+        debug!("Publishing OTA status: {:?}", status);
+        let payload = serde_json::to_vec(status)?;
+        Ok(self.mqtt_client.publish(OTA_STATUS_TOPIC, QoS::AtMostOnce, false, &payload)?)
+    }
+}
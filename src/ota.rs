@@ -0,0 +1,164 @@
+use esp_idf_sys::{
+    esp, esp_ota_abort, esp_ota_begin, esp_ota_end, esp_ota_get_next_update_partition,
+    esp_ota_handle_t, esp_ota_set_boot_partition, esp_ota_write, esp_partition_read,
+    esp_partition_t, esp_restart, OTA_SIZE_UNKNOWN,
+};
+
+use serde::Serialize;
+
+use log::*;
+
+const PUBLIC_KEY_HEX: &str = env!("GREYWATER_OTA_PUBLIC_KEY");
+
+#[derive(Debug)]
+pub enum OtaError {
+    NoUpdatePartition,
+    Flash,
+    BadPublicKey,
+    InvalidSignature,
+}
+
+impl core::fmt::Display for OtaError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            OtaError::NoUpdatePartition => write!(f, "no free OTA partition to write into"),
+            OtaError::Flash => write!(f, "flash read/write failed"),
+            OtaError::BadPublicKey => write!(f, "GREYWATER_OTA_PUBLIC_KEY is not a valid ed25519 key"),
+            OtaError::InvalidSignature => write!(f, "firmware image failed signature verification"),
+        }
+    }
+}
+
+impl std::error::Error for OtaError {}
+
+// Reported on `greywater/ota/status` so a failed update (bad signature,
+// flash error) is visible remotely instead of just leaving the operator
+// wondering why the device never rebooted. An image stream that simply
+// stops arriving produces no status.
+#[derive(Debug, Serialize)]
+#[serde(tag = "state")]
+pub enum OtaStatus {
+    Started,
+    Progress { bytes_written: usize },
+    VerifyFailed,
+    Failed { reason: String },
+    Success,
+}
+
+// Streams an incoming firmware image into the inactive OTA partition.
+// Nothing is marked bootable until `finish` verifies an ed25519 signature
+// over the whole image, so a partial or corrupted stream just leaves the
+// device running its current firmware.
+pub struct OtaUpdate {
+    handle: esp_ota_handle_t,
+    partition: *const esp_partition_t,
+    written: usize,
+}
+
+impl OtaUpdate {
+    pub fn begin() -> Result<Self, OtaError> {
+        let partition = unsafe { esp_ota_get_next_update_partition(core::ptr::null()) };
+        if partition.is_null() {
+            return Err(OtaError::NoUpdatePartition);
+        }
+
+        let mut handle: esp_ota_handle_t = 0;
+        esp!(unsafe { esp_ota_begin(partition, OTA_SIZE_UNKNOWN as usize, &mut handle) })
+            .map_err(|_| OtaError::Flash)?;
+
+        info!("OTA update started");
+        Ok(OtaUpdate { handle, partition, written: 0 })
+    }
+
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<usize, OtaError> {
+        esp!(unsafe { esp_ota_write(self.handle, chunk.as_ptr() as *const _, chunk.len()) })
+            .map_err(|_| OtaError::Flash)?;
+
+        self.written += chunk.len();
+        debug!("OTA: {} bytes written", self.written);
+        Ok(self.written)
+    }
+
+    // Releases the OTA handle without marking anything bootable. Called
+    // when a stream is abandoned mid-write so the handle isn't left
+    // dangling for the next attempt. Bytes already written stay in the
+    // inactive partition; it is simply never made the boot partition.
+    pub fn abort(self) {
+        if let Err(err) = esp!(unsafe { esp_ota_abort(self.handle) }) {
+            warn!("Failed to abort in-progress OTA update: {:?}", err);
+        }
+    }
+
+    // Verifies `signature` over the image just streamed into flash, then
+    // marks that partition bootable. The image is read back from flash
+    // rather than kept in RAM alongside each incoming chunk.
+    //
+    // Every rejection path below aborts the handle before returning: an
+    // unauthenticated caller on `greywater/ota/commit` can trivially force
+    // a bad signature, and leaving the handle open would leak it (and
+    // eventually exhaust esp-idf's OTA bookkeeping) on every such attempt.
+    pub fn finish(self, signature: &[u8; 64]) -> Result<(), OtaError> {
+        let public_key = match decode_public_key() {
+            Ok(public_key) => public_key,
+            Err(err) => {
+                self.abort();
+                return Err(err);
+            }
+        };
+
+        let mut image = vec![0u8; self.written];
+        if esp!(unsafe {
+            esp_partition_read(self.partition, 0, image.as_mut_ptr() as *mut _, image.len())
+        })
+        .is_err()
+        {
+            self.abort();
+            return Err(OtaError::Flash);
+        }
+
+        let signature = match salty::Signature::try_from(signature.as_slice()) {
+            Ok(signature) => signature,
+            Err(_) => {
+                self.abort();
+                return Err(OtaError::InvalidSignature);
+            }
+        };
+
+        if public_key.verify(&image, &signature).is_err() {
+            self.abort();
+            return Err(OtaError::InvalidSignature);
+        }
+
+        esp!(unsafe { esp_ota_end(self.handle) }).map_err(|_| OtaError::Flash)?;
+
+        esp!(unsafe { esp_ota_set_boot_partition(self.partition) }).map_err(|_| OtaError::Flash)?;
+
+        info!("OTA update verified and marked bootable");
+        Ok(())
+    }
+}
+
+fn decode_public_key() -> Result<salty::PublicKey, OtaError> {
+    let mut bytes = [0u8; 32];
+    hex_decode(PUBLIC_KEY_HEX, &mut bytes).map_err(|_| OtaError::BadPublicKey)?;
+    salty::PublicKey::try_from(&bytes).map_err(|_| OtaError::BadPublicKey)
+}
+
+fn hex_decode(hex: &str, out: &mut [u8]) -> Result<(), ()> {
+    if hex.len() != out.len() * 2 {
+        return Err(());
+    }
+
+    for (byte, chunk) in out.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let hi = (chunk[0] as char).to_digit(16).ok_or(())?;
+        let lo = (chunk[1] as char).to_digit(16).ok_or(())?;
+        *byte = ((hi << 4) | lo) as u8;
+    }
+
+    Ok(())
+}
+
+pub fn reboot_into_new_image() -> ! {
+    info!("Rebooting into updated firmware");
+    unsafe { esp_restart() }
+}
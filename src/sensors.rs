@@ -1,3 +1,7 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use core::task::{Context, Poll};
 use std::time::Duration;
 
 use embedded_hal::{
@@ -11,12 +15,110 @@ use esp_idf_hal::{
     serial::{Serial, Uart, Tx, Rx}
 };
 
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::{Duration as EmbassyDuration, Timer};
 use log::*;
 use nb::block;
-use heapless::spsc::Consumer;
 
+// HC-SR04's datasheet caps range at ~6m, which an echo pulse takes about
+// 35ms to round-trip; US-100's response over UART is comparably slow. 38ms
+// gives both a little headroom without letting a missing echo hang the
+// sampling task.
+const ECHO_TIMEOUT: EmbassyDuration = EmbassyDuration::from_millis(38);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorError {
+    // No echo (or no response byte) arrived within `ECHO_TIMEOUT`: target
+    // out of range, sensor disconnected, or a tank that's simply empty
+    // past the sensor's max depth.
+    Timeout,
+    // The underlying UART/GPIO peripheral reported an error.
+    Hardware,
+}
+
+impl core::fmt::Display for SensorError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SensorError::Timeout => write!(f, "timed out waiting for sensor response"),
+            SensorError::Hardware => write!(f, "sensor hardware error"),
+        }
+    }
+}
+
+impl std::error::Error for SensorError {}
+
+// Never used as `dyn UltrasonicSensor`, so the usual capture-semantics/
+// auto-trait pitfalls `async_fn_in_trait` warns about don't apply here.
+#[allow(async_fn_in_trait)]
 pub trait UltrasonicSensor {
-    fn distance_in_cms(&mut self) -> f32;
+    async fn distance_in_cms(&mut self) -> Result<f32, SensorError>;
+}
+
+// Shared between an echo pin's ISR and the future that awaits its pulse
+// width. The ISR fires on every edge, so we count edges rather than trying
+// to tell rising from falling: the first edge is the start of the echo,
+// the second is the end, and anything past that is a stray interrupt left
+// over from the previous reading (dropped until the next `reset`).
+pub struct Echo {
+    start_nanos: AtomicU64,
+    end_nanos: AtomicU64,
+    edges: AtomicU8,
+    waker: AtomicWaker,
+}
+
+impl Echo {
+    pub const fn new() -> Self {
+        Echo {
+            start_nanos: AtomicU64::new(0),
+            end_nanos: AtomicU64::new(0),
+            edges: AtomicU8::new(0),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    // Called from the echo pin's interrupt handler. Kept lock-free so it's
+    // safe to call directly from ISR context.
+    pub fn on_edge(&self, now: Duration) {
+        match self.edges.fetch_add(1, Ordering::AcqRel) {
+            0 => self.start_nanos.store(now.as_nanos() as u64, Ordering::Release),
+            1 => {
+                self.end_nanos.store(now.as_nanos() as u64, Ordering::Release);
+                self.waker.wake();
+            }
+            _ => debug!("Stray echo edge ignored"),
+        }
+    }
+
+    fn reset(&self) {
+        self.edges.store(0, Ordering::Release);
+    }
+
+    fn pulse_width(&self) -> Option<Duration> {
+        if self.edges.load(Ordering::Acquire) >= 2 {
+            let start = self.start_nanos.load(Ordering::Acquire);
+            let end = self.end_nanos.load(Ordering::Acquire);
+            Some(Duration::from_nanos(end - start))
+        } else {
+            None
+        }
+    }
+}
+
+struct EchoFuture<'a> {
+    echo: &'a Echo,
+}
+
+impl<'a> Future for EchoFuture<'a> {
+    type Output = Duration;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.echo.waker.register(cx.waker());
+
+        match self.echo.pulse_width() {
+            Some(width) => Poll::Ready(width),
+            None => Poll::Pending,
+        }
+    }
 }
 
 // NOTE: This is necessarily a macro because the pins are different *types*
@@ -24,10 +126,8 @@ pub trait UltrasonicSensor {
 // function or struct. So that's cool...
 #[macro_export]
 macro_rules! hc_sr04 {
-    ($trigger_pin:expr, $echo_pin:expr, $queue:expr) => {
+    ($trigger_pin:expr, $echo_pin:expr, $echo:expr) => {
         {
-            let (mut tx, response) = unsafe { $queue.split() };
-
             let trigger_pin = $trigger_pin
                 .into_output()
                 .expect("Setting trigger pin as output")
@@ -42,12 +142,12 @@ macro_rules! hc_sr04 {
             unsafe {
                 echo_pin.into_subscribed(move ||{
                     let now = EspSystemTime {}.now();
-                    tx.enqueue(now).expect("Enqueuing time");
+                    $echo.on_edge(now);
                 }, InterruptType::AnyEdge)
                     .expect("Setting edge interrupt for echo pin");
             }
 
-            $crate::sensors::HcSr04::new(trigger_pin, response)
+            $crate::sensors::HcSr04::new(trigger_pin, &$echo)
         }
     };
 }
@@ -55,37 +155,31 @@ macro_rules! hc_sr04 {
 // Driver for any HcSr04 compatible device (RCWL-1601, US-100 (without UART)).
 pub struct HcSr04 {
     trigger_pin: GpioPin<Output>,
-    response: Consumer<'static, Duration, 2>
+    echo: &'static Echo,
 }
 
 impl HcSr04 {
-    pub fn new(trigger_pin: GpioPin<Output>, response: Consumer<'static, Duration, 2>) -> HcSr04 {
-        HcSr04 { trigger_pin, response }
+    pub fn new(trigger_pin: GpioPin<Output>, echo: &'static Echo) -> HcSr04 {
+        HcSr04 { trigger_pin, echo }
     }
 }
 
 impl UltrasonicSensor for HcSr04 {
-    fn distance_in_cms(&mut self) -> f32 {
+    async fn distance_in_cms(&mut self) -> Result<f32, SensorError> {
+        self.echo.reset();
+
         debug!("Starting trigger pulse");
         self.trigger_pin.set_high().expect("Starting trigger pulse");
         delay::Ets.delay_us(10u8);
         self.trigger_pin.set_low().expect("Ending trigger pulse");
         debug!("Pulse done.");
 
-        let mut blocking_dequeue = move || {
-            while !self.response.ready() {}
-            unsafe { self.response.dequeue_unchecked() }
-        };
-
-        let start = blocking_dequeue();
-        debug!("Got start: {:?}", start);
-        let end = blocking_dequeue();
-        debug!("Got end: {:?}", end);
+        let width = embassy_time::with_timeout(ECHO_TIMEOUT, EchoFuture { echo: self.echo })
+            .await
+            .map_err(|_| SensorError::Timeout)?;
+        debug!("Echo width: {:?}", width);
 
-        let raw = (end - start).as_micros() as f32 / 58.0;
-        debug!("Raw: {}", raw);
-
-        raw
+        Ok(width.as_micros() as f32 / 58.0)
     }
 }
 
@@ -102,18 +196,34 @@ impl<UART: Uart> Us100<UART> {
     }
 }
 impl<UART: Uart> UltrasonicSensor for Us100<UART> {
-    fn distance_in_cms(&mut self) -> f32 {
+    async fn distance_in_cms(&mut self) -> Result<f32, SensorError> {
         debug!("US100: Sending bytes");
-        block!(self.tx.write(0x55)).expect("Failed to send to serial connection");
-        block!(self.tx.flush()).expect("Failed flush");
+        block!(self.tx.write(0x55)).map_err(|_| SensorError::Hardware)?;
+        block!(self.tx.flush()).map_err(|_| SensorError::Hardware)?;
         debug!("US100: Done sending bytes");
-        debug!("US100: Reading first byte");
-        let first = block!(self.rx.read()).expect("Reading first byte");
-        debug!("US100: Reading second byte");
-        let second = block!(self.rx.read()).expect("Reading second byte");
+
+        let (first, second) = embassy_time::with_timeout(ECHO_TIMEOUT, async {
+            debug!("US100: Reading first byte");
+            let first = read_byte(&mut self.rx).await?;
+            debug!("US100: Reading second byte");
+            let second = read_byte(&mut self.rx).await?;
+            Ok::<_, SensorError>((first, second))
+        })
+        .await
+        .map_err(|_| SensorError::Timeout)??;
+
         let mms = ((first as u16) << 8) | second as u16;
 
-        mms as f32 / 100 as f32
+        Ok(mms as f32 / 10 as f32)
     }
 }
 
+async fn read_byte<UART: Uart>(rx: &mut Rx<UART>) -> Result<u8, SensorError> {
+    loop {
+        match rx.read() {
+            Ok(byte) => return Ok(byte),
+            Err(nb::Error::WouldBlock) => Timer::after(EmbassyDuration::from_micros(100)).await,
+            Err(nb::Error::Other(_)) => return Err(SensorError::Hardware),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use embedded_svc::sys_time::SystemTime;
+use esp_idf_svc::systime::EspSystemTime;
+use serde::{Deserialize, Serialize};
+
+// Bumped whenever a field is added or a meaning changes, so a consumer can
+// tell an old packet from a new one instead of guessing from its length.
+pub const SCHEMA_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SensorId {
+    ClearTank,
+    Bioreactor,
+}
+
+// A single tank reading, ready to publish. Carries both the median the
+// filter settled on and the last raw sample that fed it, so a downstream
+// consumer can tell a quiet tank from a flaky sensor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Telemetry {
+    pub schema_version: u8,
+    pub sensor: SensorId,
+    pub timestamp: Duration,
+    pub median_distance_cm: f32,
+    pub raw_distance_cm: f32,
+    pub sample_count: usize,
+}
+
+#[derive(Debug)]
+pub enum TelemetryError {
+    #[cfg(not(feature = "telemetry-json"))]
+    Encode(postcard::Error),
+    #[cfg(feature = "telemetry-json")]
+    Encode(serde_json::Error),
+}
+
+impl core::fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TelemetryError::Encode(err) => write!(f, "failed to encode telemetry: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TelemetryError {}
+
+impl Telemetry {
+    pub fn new(
+        sensor: SensorId,
+        median_distance_cm: f32,
+        raw_distance_cm: f32,
+        sample_count: usize,
+    ) -> Self {
+        Telemetry {
+            schema_version: SCHEMA_VERSION,
+            sensor,
+            timestamp: EspSystemTime {}.now(),
+            median_distance_cm,
+            raw_distance_cm,
+            sample_count,
+        }
+    }
+
+    // Compact binary encoding for MQTT by default; swap to pretty JSON with
+    // the `telemetry-json` feature when debugging on the bench.
+    #[cfg(not(feature = "telemetry-json"))]
+    pub fn encode(&self) -> Result<Vec<u8>, TelemetryError> {
+        postcard::to_allocvec(self).map_err(TelemetryError::Encode)
+    }
+
+    #[cfg(feature = "telemetry-json")]
+    pub fn encode(&self) -> Result<Vec<u8>, TelemetryError> {
+        serde_json::to_vec_pretty(self).map_err(TelemetryError::Encode)
+    }
+}